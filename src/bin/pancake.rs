@@ -1,75 +1,43 @@
-use std::collections::HashMap;
-
-use dessert::{map_op, run_vm, Instruction, VMError};
+use dessert::{parse, run_vm, VMError, VmConfig};
 
 fn main() -> Result<(), VMError> {
-    use Instruction::*;
-
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <instruction_file>", args[0]);
-        eprintln!("Runs the specified instruction file in the stack-based VM (pancake).");
-        std::process::exit(1);
-    }
-
-    let binding = std::fs::read_to_string(&args[1])?;
-    let lines = binding
-        .lines()
-        .map(|line| line.trim())
-        .filter(|line| !line.is_empty() && !line.starts_with("//"))
-        .collect::<Vec<&str>>();
-
-    let mut raw_instructions = Vec::new();
-
-    let mut labels = HashMap::new();
-    let mut pc = 0;
-    for line in &lines {
-        if let Some(label) = line.strip_suffix(':') {
-            labels.insert(label.to_string(), pc);
-            continue;
-        } else {
-            let mut parts = line.split_whitespace();
-            let op = parts.next().unwrap();
-            let arg = parts.next();
-            let instr = map_op((op, arg))?;
-            raw_instructions.push(instr);
-            pc += 1;
-        }
-    }
 
-    // resolve labels in instructions
-    let mut instructions = Vec::new();
-    for instr in raw_instructions {
-        match instr {
-            Jump(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
-                instructions.push(JumpResolved(addr));
-            }
-            JumpZ(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
-                instructions.push(JumpZResolved(addr));
-            }
-            JumpNotZ(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
-                instructions.push(JumpNotZResolved(addr));
+    let mut path = None;
+    let mut config = VmConfig::default();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--stack-size" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| VMError::MissingArgument("--stack-size".into()))?;
+                config.max_stack_size = value.parse::<usize>()?;
             }
-            Call(label) => {
-                let addr = *labels
-                    .get(&label)
-                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
-                instructions.push(CallResolved(addr));
+            "--memory-size" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| VMError::MissingArgument("--memory-size".into()))?;
+                config.max_memory_size = value.parse::<usize>()?;
             }
-            other => instructions.push(other),
+            _ => path = Some(arg.clone()),
         }
     }
+    let config = VmConfig::new(config.max_stack_size, config.max_memory_size);
+
+    let Some(path) = path else {
+        eprintln!(
+            "Usage: {} [--stack-size N] [--memory-size N] <instruction_file>",
+            args[0]
+        );
+        eprintln!("Runs the specified instruction file in the stack-based VM (pancake).");
+        std::process::exit(1);
+    };
+
+    let source = std::fs::read_to_string(&path)?;
+    let instructions = parse(&source)?;
 
-    run_vm(instructions)?;
+    run_vm(instructions, config)?;
 
     Ok(())
 }
@@ -0,0 +1,218 @@
+//! Compact binary encoding for a fully label-resolved instruction stream,
+//! so pre-compiled pancake programs can be shipped and run without their
+//! source.
+
+use std::fmt;
+
+use crate::{parse, run_vm, Instruction, VMError, VmConfig};
+
+const MAGIC: [u8; 4] = *b"PNKB";
+const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum DisasmError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    InvalidOpcode(u8),
+    UnexpectedEof,
+}
+
+impl std::error::Error for DisasmError {}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisasmError::BadMagic => write!(f, "Missing or invalid bytecode magic header"),
+            DisasmError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {}", v),
+            DisasmError::InvalidOpcode(op) => write!(f, "Invalid opcode byte {:#04x}", op),
+            DisasmError::UnexpectedEof => write!(f, "Unexpected end of bytecode"),
+        }
+    }
+}
+
+fn opcode_of(instr: &Instruction) -> Result<u8, VMError> {
+    use Instruction::*;
+
+    match instr {
+        Swap => Ok(0),
+        Push(_) => Ok(1),
+        Pop => Ok(2),
+        Print => Ok(3),
+        Dup => Ok(30),
+        Over => Ok(31),
+        Rot => Ok(32),
+        Pick(_) => Ok(33),
+        Read => Ok(34),
+        Add => Ok(4),
+        Sub => Ok(5),
+        Mul => Ok(6),
+        Div => Ok(7),
+        Mod => Ok(8),
+        Pow => Ok(9),
+        Shl => Ok(10),
+        Shr => Ok(11),
+        And => Ok(12),
+        Or => Ok(13),
+        Xor => Ok(14),
+        Eq => Ok(15),
+        Ne => Ok(16),
+        Lt => Ok(17),
+        Gt => Ok(18),
+        Le => Ok(19),
+        Ge => Ok(20),
+        Load => Ok(21),
+        Store => Ok(22),
+        JumpResolved(_) => Ok(23),
+        JumpZResolved(_) => Ok(24),
+        JumpNotZResolved(_) => Ok(25),
+        CallResolved(_) => Ok(26),
+        SetHandlerResolved(_) => Ok(27),
+        Ret => Ok(28),
+        Halt => Ok(29),
+        other => Err(VMError::InvalidInstruction(format!(
+            "Cannot encode unresolved instruction: {:?}",
+            other
+        ))),
+    }
+}
+
+/// Encodes a resolved instruction stream into the pancake bytecode format.
+pub fn encode(instructions: &[Instruction]) -> Result<Vec<u8>, VMError> {
+    use Instruction::*;
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + instructions.len() * 3);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+
+    for instr in instructions {
+        bytes.push(opcode_of(instr)?);
+        match instr {
+            Push(v) | Pick(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+            JumpResolved(addr)
+            | JumpZResolved(addr)
+            | JumpNotZResolved(addr)
+            | CallResolved(addr)
+            | SetHandlerResolved(addr) => bytes.extend_from_slice(&(*addr as u32).to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Reads a pancake source file and assembles it into bytecode.
+pub fn assemble(path: &str) -> Result<Vec<u8>, VMError> {
+    let source = std::fs::read_to_string(path)?;
+    let instructions = parse(&source)?;
+    encode(&instructions)
+}
+
+/// Decodes pancake bytecode back into an instruction stream.
+pub fn disassemble(bytes: &[u8]) -> Result<Vec<Instruction>, DisasmError> {
+    use Instruction::*;
+
+    if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(DisasmError::BadMagic);
+    }
+    let version = bytes[MAGIC.len()];
+    if version != VERSION {
+        return Err(DisasmError::UnsupportedVersion(version));
+    }
+
+    let mut instructions = Vec::new();
+    let mut pos = MAGIC.len() + 1;
+
+    let read_u16 = |bytes: &[u8], pos: usize| -> Result<u16, DisasmError> {
+        let slice = bytes
+            .get(pos..pos + 2)
+            .ok_or(DisasmError::UnexpectedEof)?;
+        Ok(u16::from_le_bytes(slice.try_into().unwrap()))
+    };
+    let read_u32 = |bytes: &[u8], pos: usize| -> Result<u32, DisasmError> {
+        let slice = bytes
+            .get(pos..pos + 4)
+            .ok_or(DisasmError::UnexpectedEof)?;
+        Ok(u32::from_le_bytes(slice.try_into().unwrap()))
+    };
+
+    while pos < bytes.len() {
+        let opcode = bytes[pos];
+        pos += 1;
+
+        let instr = match opcode {
+            0 => Swap,
+            1 => {
+                let v = read_u16(bytes, pos)?;
+                pos += 2;
+                Push(v)
+            }
+            2 => Pop,
+            3 => Print,
+            4 => Add,
+            5 => Sub,
+            6 => Mul,
+            7 => Div,
+            8 => Mod,
+            9 => Pow,
+            10 => Shl,
+            11 => Shr,
+            12 => And,
+            13 => Or,
+            14 => Xor,
+            15 => Eq,
+            16 => Ne,
+            17 => Lt,
+            18 => Gt,
+            19 => Le,
+            20 => Ge,
+            21 => Load,
+            22 => Store,
+            23 => {
+                let addr = read_u32(bytes, pos)?;
+                pos += 4;
+                JumpResolved(addr as usize)
+            }
+            24 => {
+                let addr = read_u32(bytes, pos)?;
+                pos += 4;
+                JumpZResolved(addr as usize)
+            }
+            25 => {
+                let addr = read_u32(bytes, pos)?;
+                pos += 4;
+                JumpNotZResolved(addr as usize)
+            }
+            26 => {
+                let addr = read_u32(bytes, pos)?;
+                pos += 4;
+                CallResolved(addr as usize)
+            }
+            27 => {
+                let addr = read_u32(bytes, pos)?;
+                pos += 4;
+                SetHandlerResolved(addr as usize)
+            }
+            28 => Ret,
+            29 => Halt,
+            30 => Dup,
+            31 => Over,
+            32 => Rot,
+            33 => {
+                let n = read_u16(bytes, pos)?;
+                pos += 2;
+                Pick(n)
+            }
+            34 => Read,
+            other => return Err(DisasmError::InvalidOpcode(other)),
+        };
+        instructions.push(instr);
+    }
+
+    Ok(instructions)
+}
+
+/// Runs a bytecode buffer directly, under the default `VmConfig`.
+pub fn run_bytecode(bytes: &[u8]) -> Result<(), VMError> {
+    let instructions = disassemble(bytes)?;
+    run_vm(instructions, VmConfig::default())
+}
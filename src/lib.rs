@@ -1,8 +1,48 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-type Stack = Vec<u16>;
+mod bytecode;
+pub use bytecode::{assemble, disassemble, run_bytecode, DisasmError};
+
+pub type Stack = Vec<u16>;
 type Memory = Vec<u16>;
 const MAX_MEMORY_SIZE: usize = 1_000_000;
+const DEFAULT_STACK_SIZE: usize = 256;
+const MAX_STACK_SIZE: usize = 65_535;
+
+/// Resource limits (and cooperative controls) applied while running a program.
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    pub max_stack_size: usize,
+    pub max_memory_size: usize,
+    /// Checked once per instruction; setting it lets an embedder (or a
+    /// Ctrl-C handler) request cooperative termination without killing
+    /// the host process.
+    pub interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            max_stack_size: DEFAULT_STACK_SIZE,
+            max_memory_size: MAX_MEMORY_SIZE,
+            interrupt: None,
+        }
+    }
+}
+
+impl VmConfig {
+    /// Builds a config, clamping `max_stack_size` to `MAX_STACK_SIZE`.
+    pub fn new(max_stack_size: usize, max_memory_size: usize) -> Self {
+        VmConfig {
+            max_stack_size: max_stack_size.min(MAX_STACK_SIZE),
+            max_memory_size,
+            interrupt: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum VMError {
@@ -14,6 +54,12 @@ pub enum VMError {
     DivisionByZero,
     CallStackUnderflow,
     MemoryAccessOutOfBounds(usize),
+    ArithmeticOverflow { op: String, a: u16, b: u16 },
+    StackOverflow,
+    Interrupted,
+    BytecodeError(DisasmError),
+    UndefinedNative(String),
+    InvalidInput(String),
 
     IoError(std::io::Error),
     ParseIntError(std::num::ParseIntError),
@@ -33,6 +79,14 @@ impl fmt::Display for VMError {
             VMError::MemoryAccessOutOfBounds(addr) => {
                 write!(f, "Memory access out of bounds at address {}", addr)
             }
+            VMError::ArithmeticOverflow { op, a, b } => {
+                write!(f, "Arithmetic overflow in '{}' ({}, {})", op, a, b)
+            }
+            VMError::StackOverflow => write!(f, "Stack overflow encountered"),
+            VMError::Interrupted => write!(f, "Execution was interrupted"),
+            VMError::BytecodeError(err) => write!(f, "Bytecode error: {}", err),
+            VMError::UndefinedNative(name) => write!(f, "Undefined native function '{}'", name),
+            VMError::InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
             VMError::IoError(err) => write!(f, "I/O error: {}", err),
             VMError::ParseIntError(err) => write!(f, "Parse integer error: {}", err),
         }
@@ -51,6 +105,12 @@ impl From<std::num::ParseIntError> for VMError {
     }
 }
 
+impl From<DisasmError> for VMError {
+    fn from(error: DisasmError) -> Self {
+        VMError::BytecodeError(error)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Instruction {
     // stack operations
@@ -58,12 +118,34 @@ pub enum Instruction {
     Push(u16),
     Pop,
     Print,
+    Read,
+    Dup,
+    Over,
+    Rot,
+    Pick(u16),
 
     // arithmetic operations
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Pow,
+
+    // bitwise operations
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+
+    // comparison operations
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
 
     // memory operations
     Load,
@@ -75,26 +157,82 @@ pub enum Instruction {
     JumpNotZ(String),
     Call(String),
     Ret,
+    SetHandler(String),
 
     // resolved control flow (after label resolution)
     JumpResolved(usize),
     JumpZResolved(usize),
     JumpNotZResolved(usize),
     CallResolved(usize),
+    SetHandlerResolved(usize),
 
     // program control
     Halt,
+    Native(String),
 
     // for label definitions
     Label(String),
 }
 
+type NativeFn = Box<dyn FnMut(&mut Stack) -> Result<(), VMError>>;
+
+/// Maps names to host-provided functions that pancake programs can call
+/// via the `native` instruction. Each function gets mutable access to the
+/// operand stack to pop arguments and push results.
+#[derive(Default)]
+pub struct NativeRegistry {
+    natives: HashMap<String, NativeFn>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        NativeRegistry {
+            natives: HashMap::new(),
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        f: impl FnMut(&mut Stack) -> Result<(), VMError> + 'static,
+    ) {
+        self.natives.insert(name.into(), Box::new(f));
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Context {
     pub stack: Stack,
     pub memory: Memory,
     pub pc: usize,
     pub call_stack: Vec<usize>,
+    pub handler: Option<usize>,
+}
+
+/// Numeric code pushed onto the stack when a fault is trapped, so the
+/// guest-installed handler can branch on what went wrong.
+fn trap_code(err: &VMError) -> Option<u16> {
+    match err {
+        VMError::DivisionByZero => Some(1),
+        VMError::MemoryAccessOutOfBounds(_) => Some(2),
+        VMError::StackUnderflow => Some(3),
+        VMError::Interrupted => Some(4),
+        _ => None,
+    }
+}
+
+/// Dispatches a fault to the installed handler if one is set and the fault
+/// is recoverable, otherwise returns it as a hard error.
+fn trap(context: &mut Context, err: VMError) -> Result<(), VMError> {
+    match (trap_code(&err), context.handler) {
+        (Some(code), Some(handler)) => {
+            context.stack.push(code);
+            context.call_stack.push(context.pc);
+            context.pc = handler;
+            Ok(())
+        }
+        _ => Err(err),
+    }
 }
 
 pub fn map_op(s: (&str, Option<&str>)) -> Result<Instruction, VMError> {
@@ -110,10 +248,34 @@ pub fn map_op(s: (&str, Option<&str>)) -> Result<Instruction, VMError> {
         }
         "pop" => Ok(Pop),
         "print" => Ok(Print),
+        "read" => Ok(Read),
+        "dup" => Ok(Dup),
+        "over" => Ok(Over),
+        "rot" => Ok(Rot),
+        "pick" => {
+            let arg = s.1.ok_or_else(|| VMError::MissingArgument("pick".into()))?;
+            let n = arg
+                .parse::<u16>()
+                .map_err(|_| VMError::InvalidPushValue(arg.into()))?;
+            Ok(Pick(n))
+        }
         "add" => Ok(Add),
         "sub" => Ok(Sub),
         "mul" => Ok(Mul),
         "div" => Ok(Div),
+        "mod" => Ok(Mod),
+        "pow" => Ok(Pow),
+        "shl" => Ok(Shl),
+        "shr" => Ok(Shr),
+        "and" => Ok(And),
+        "or" => Ok(Or),
+        "xor" => Ok(Xor),
+        "eq" => Ok(Eq),
+        "ne" => Ok(Ne),
+        "lt" => Ok(Lt),
+        "gt" => Ok(Gt),
+        "le" => Ok(Le),
+        "ge" => Ok(Ge),
         "load" => Ok(Load),
         "store" => Ok(Store),
         "jump" => {
@@ -141,25 +303,152 @@ pub fn map_op(s: (&str, Option<&str>)) -> Result<Instruction, VMError> {
             Ok(Call(label))
         }
         "ret" => Ok(Ret),
+        "native" => {
+            let name = s
+                .1
+                .ok_or_else(|| VMError::MissingArgument("native".into()))?
+                .to_string();
+            Ok(Native(name))
+        }
+        "sethandler" => {
+            let label = s
+                .1
+                .ok_or_else(|| VMError::MissingArgument("sethandler".into()))?
+                .to_string();
+            Ok(SetHandler(label))
+        }
         "halt" => Ok(Halt),
         _ => Err(VMError::InvalidInstruction(s.0.into())),
     }
 }
 
-pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
+/// Parses pancake source into a fully label-resolved instruction stream.
+pub fn parse(source: &str) -> Result<Vec<Instruction>, VMError> {
     use Instruction::*;
 
+    let lines = source
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .collect::<Vec<&str>>();
+
+    let mut raw_instructions = Vec::new();
+
+    let mut labels = HashMap::new();
+    let mut pc = 0;
+    for line in &lines {
+        if let Some(label) = line.strip_suffix(':') {
+            labels.insert(label.to_string(), pc);
+            continue;
+        } else {
+            let mut parts = line.split_whitespace();
+            let op = parts.next().unwrap();
+            let arg = parts.next();
+            let instr = map_op((op, arg))?;
+            raw_instructions.push(instr);
+            pc += 1;
+        }
+    }
+
+    // resolve labels in instructions
+    let mut instructions = Vec::new();
+    for instr in raw_instructions {
+        match instr {
+            Jump(label) => {
+                let addr = *labels
+                    .get(&label)
+                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
+                instructions.push(JumpResolved(addr));
+            }
+            JumpZ(label) => {
+                let addr = *labels
+                    .get(&label)
+                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
+                instructions.push(JumpZResolved(addr));
+            }
+            JumpNotZ(label) => {
+                let addr = *labels
+                    .get(&label)
+                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
+                instructions.push(JumpNotZResolved(addr));
+            }
+            Call(label) => {
+                let addr = *labels
+                    .get(&label)
+                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
+                instructions.push(CallResolved(addr));
+            }
+            SetHandler(label) => {
+                let addr = *labels
+                    .get(&label)
+                    .ok_or_else(|| VMError::UndefinedLabel(label.clone()))?;
+                instructions.push(SetHandlerResolved(addr));
+            }
+            other => instructions.push(other),
+        }
+    }
+
+    Ok(instructions)
+}
+
+pub fn run_vm(instructions: Vec<Instruction>, config: VmConfig) -> Result<(), VMError> {
+    run(instructions, config, None)
+}
+
+/// Runs a program with a registry of host-callable native functions
+/// available to its `native` instructions.
+pub fn run_vm_with_natives(
+    instructions: Vec<Instruction>,
+    registry: &mut NativeRegistry,
+    config: VmConfig,
+) -> Result<(), VMError> {
+    run(instructions, config, Some(registry))
+}
+
+fn run(
+    instructions: Vec<Instruction>,
+    config: VmConfig,
+    mut natives: Option<&mut NativeRegistry>,
+) -> Result<(), VMError> {
     let mut context = Context {
         stack: vec![],
         memory: vec![],
         pc: 0,
         call_stack: vec![],
+        handler: None,
     };
 
     while context.pc < instructions.len() {
+        if let Some(flag) = &config.interrupt {
+            if flag.swap(false, Ordering::Relaxed) {
+                trap(&mut context, VMError::Interrupted)?;
+                continue;
+            }
+        }
+
         let ins = &instructions[context.pc];
+        let reborrowed = natives.as_deref_mut();
+        match step(ins, &mut context, &instructions, &config, reborrowed) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(err) => trap(&mut context, err)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes a single instruction, returning `Ok(true)` on `Halt`.
+fn step(
+    ins: &Instruction,
+    context: &mut Context,
+    instructions: &[Instruction],
+    config: &VmConfig,
+    natives: Option<&mut NativeRegistry>,
+) -> Result<bool, VMError> {
+    use Instruction::*;
 
-        match ins {
+    match ins {
             Swap => {
                 let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
                 let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
@@ -169,6 +458,9 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                 context.pc += 1;
             }
             Push(v) => {
+                if context.stack.len() >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
                 context.stack.push(*v);
                 context.pc += 1;
             }
@@ -176,6 +468,45 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                 context.stack.pop().ok_or(VMError::StackUnderflow)?;
                 context.pc += 1;
             }
+            Dup => {
+                let top = *context.stack.last().ok_or(VMError::StackUnderflow)?;
+                if context.stack.len() >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
+                context.stack.push(top);
+                context.pc += 1;
+            }
+            Over => {
+                let len = context.stack.len();
+                if len < 2 {
+                    return Err(VMError::StackUnderflow);
+                }
+                if context.stack.len() >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
+                context.stack.push(context.stack[len - 2]);
+                context.pc += 1;
+            }
+            Rot => {
+                let len = context.stack.len();
+                if len < 3 {
+                    return Err(VMError::StackUnderflow);
+                }
+                context.stack[len - 3..].rotate_left(1);
+                context.pc += 1;
+            }
+            Pick(n) => {
+                let len = context.stack.len();
+                let n = *n as usize;
+                if n >= len {
+                    return Err(VMError::StackUnderflow);
+                }
+                if len >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
+                context.stack.push(context.stack[len - 1 - n]);
+                context.pc += 1;
+            }
             Print => {
                 if let Some(&value) = context.stack.last() {
                     println!("{}", value);
@@ -184,22 +515,51 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                 }
                 context.pc += 1;
             }
+            Read => {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input)?;
+                let token = input
+                    .split_whitespace()
+                    .next()
+                    .ok_or_else(|| VMError::InvalidInput("no input available".into()))?;
+                let value: u16 = token.parse()?;
+                if context.stack.len() >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
+                context.stack.push(value);
+                context.pc += 1;
+            }
             Add => {
                 let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
                 let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
-                context.stack.push(a + b);
+                let result = a.checked_add(b).ok_or(VMError::ArithmeticOverflow {
+                    op: "add".into(),
+                    a,
+                    b,
+                })?;
+                context.stack.push(result);
                 context.pc += 1;
             }
             Sub => {
                 let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
                 let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
-                context.stack.push(a - b);
+                let result = a.checked_sub(b).ok_or(VMError::ArithmeticOverflow {
+                    op: "sub".into(),
+                    a,
+                    b,
+                })?;
+                context.stack.push(result);
                 context.pc += 1;
             }
             Mul => {
                 let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
                 let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
-                context.stack.push(a * b);
+                let result = a.checked_mul(b).ok_or(VMError::ArithmeticOverflow {
+                    op: "mul".into(),
+                    a,
+                    b,
+                })?;
+                context.stack.push(result);
                 context.pc += 1;
             }
             Div => {
@@ -211,6 +571,108 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                 context.stack.push(a / b);
                 context.pc += 1;
             }
+            Mod => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                if b == 0 {
+                    return Err(VMError::DivisionByZero);
+                }
+                context.stack.push(a % b);
+                context.pc += 1;
+            }
+            Pow => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let result = a
+                    .checked_pow(b as u32)
+                    .ok_or(VMError::ArithmeticOverflow {
+                        op: "pow".into(),
+                        a,
+                        b,
+                    })?;
+                context.stack.push(result);
+                context.pc += 1;
+            }
+            Shl => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let result = a
+                    .checked_shl(b as u32)
+                    .ok_or(VMError::ArithmeticOverflow {
+                        op: "shl".into(),
+                        a,
+                        b,
+                    })?;
+                context.stack.push(result);
+                context.pc += 1;
+            }
+            Shr => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let result = a
+                    .checked_shr(b as u32)
+                    .ok_or(VMError::ArithmeticOverflow {
+                        op: "shr".into(),
+                        a,
+                        b,
+                    })?;
+                context.stack.push(result);
+                context.pc += 1;
+            }
+            And => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push(a & b);
+                context.pc += 1;
+            }
+            Or => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push(a | b);
+                context.pc += 1;
+            }
+            Xor => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push(a ^ b);
+                context.pc += 1;
+            }
+            Eq => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a == b) as u16);
+                context.pc += 1;
+            }
+            Ne => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a != b) as u16);
+                context.pc += 1;
+            }
+            Lt => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a < b) as u16);
+                context.pc += 1;
+            }
+            Gt => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a > b) as u16);
+                context.pc += 1;
+            }
+            Le => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a <= b) as u16);
+                context.pc += 1;
+            }
+            Ge => {
+                let b = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                let a = context.stack.pop().ok_or(VMError::StackUnderflow)?;
+                context.stack.push((a >= b) as u16);
+                context.pc += 1;
+            }
             Load => {
                 let addr = context.stack.pop().ok_or(VMError::StackUnderflow)? as usize;
                 let value = if addr < context.memory.len() {
@@ -229,7 +691,7 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                 let value = context.stack.pop().ok_or(VMError::StackUnderflow)?;
 
                 if addr >= context.memory.len() {
-                    if addr + 1 > MAX_MEMORY_SIZE {
+                    if addr + 1 > config.max_memory_size {
                         return Err(VMError::MemoryAccessOutOfBounds(addr));
                     }
                     context.memory.resize(addr + 1, 0);
@@ -282,6 +744,9 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                         addr
                     )));
                 }
+                if context.call_stack.len() >= config.max_stack_size {
+                    return Err(VMError::StackOverflow);
+                }
                 context.call_stack.push(context.pc + 1);
                 context.pc = *addr;
             }
@@ -291,20 +756,40 @@ pub fn run_vm(instructions: Vec<Instruction>) -> Result<(), VMError> {
                     .pop()
                     .ok_or(VMError::CallStackUnderflow)?;
             }
+            SetHandlerResolved(addr) => {
+                if *addr >= instructions.len() {
+                    return Err(VMError::UndefinedLabel(format!(
+                        "Handler address {} is out of bounds",
+                        addr
+                    )));
+                }
+                context.handler = Some(*addr);
+                context.pc += 1;
+            }
+            Native(name) => {
+                let registry = natives.ok_or_else(|| VMError::UndefinedNative(name.clone()))?;
+                let f = registry
+                    .natives
+                    .get_mut(name)
+                    .ok_or_else(|| VMError::UndefinedNative(name.clone()))?;
+                f(&mut context.stack)?;
+                context.pc += 1;
+            }
             Halt => {
-                break;
+                return Ok(true);
             }
             Label(_) => {
                 context.pc += 1;
             }
-            Jump(_) | JumpZ(_) | JumpNotZ(_) | Call(_) => {
+            Jump(_) | JumpZ(_) | JumpNotZ(_) | Call(_) | SetHandler(_) => {
                 return Err(VMError::InvalidInstruction(format!(
                     "Unresolved label at pc {}: {:?}",
                     context.pc, ins
                 )));
             }
         }
-    }
 
-    Ok(())
+    Ok(false)
 }
+
+